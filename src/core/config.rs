@@ -0,0 +1,65 @@
+use database::Database;
+use errors::*;
+use serenity::model::id::GuildId;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ConfigKeys {
+    RolesEnableLimits,
+    RolesMaxAssigned,
+    RolesMaxCustomRules,
+    RolesMaxInstructions,
+    RolesMaxWebRequests,
+    SetNickname,
+    ReverifySweepInterval,
+}
+impl ConfigKeys {
+    fn column(self) -> &'static str {
+        match self {
+            ConfigKeys::RolesEnableLimits => "roles_enable_limits",
+            ConfigKeys::RolesMaxAssigned => "roles_max_assigned",
+            ConfigKeys::RolesMaxCustomRules => "roles_max_custom_rules",
+            ConfigKeys::RolesMaxInstructions => "roles_max_instructions",
+            ConfigKeys::RolesMaxWebRequests => "roles_max_web_requests",
+            ConfigKeys::SetNickname => "set_nickname",
+            ConfigKeys::ReverifySweepInterval => "reverify_sweep_interval",
+        }
+    }
+}
+
+pub trait ConfigValue: Sized {
+    fn from_config_str(raw: Option<String>) -> Self;
+}
+impl ConfigValue for bool {
+    fn from_config_str(raw: Option<String>) -> Self {
+        raw.as_ref().map(|v| v.as_str()) == Some("true")
+    }
+}
+impl ConfigValue for u32 {
+    fn from_config_str(raw: Option<String>) -> Self {
+        raw.and_then(|v| v.parse().ok()).unwrap_or(0)
+    }
+}
+impl ConfigValue for u64 {
+    fn from_config_str(raw: Option<String>) -> Self {
+        raw.and_then(|v| v.parse().ok()).unwrap_or(0)
+    }
+}
+
+#[derive(Clone)]
+pub struct ConfigManager {
+    database: Database,
+}
+impl ConfigManager {
+    pub fn new(database: Database) -> ConfigManager {
+        ConfigManager { database }
+    }
+
+    // `guild` is `None` for keys that aren't scoped per-guild.
+    pub fn get<T: ConfigValue>(&self, guild: Option<GuildId>, key: ConfigKeys) -> Result<T> {
+        let raw = self.database.connect()?.query_cached(
+            "SELECT value FROM guild_config WHERE discord_guild_id = ?1 AND key = ?2",
+            (guild.map(|g| g.0).unwrap_or(0), key.column()),
+        ).get_opt::<String>()?;
+        Ok(T::from_config_str(raw))
+    }
+}
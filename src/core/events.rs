@@ -0,0 +1,26 @@
+use core::roles::RoleManager;
+use serenity::model::guild::Member;
+use serenity::model::id::RoleId;
+use serenity::prelude::{Context, EventHandler};
+use std::collections::HashSet;
+
+pub struct RoleEventHandler {
+    roles: RoleManager,
+}
+impl RoleEventHandler {
+    pub fn new(roles: RoleManager) -> RoleEventHandler {
+        RoleEventHandler { roles }
+    }
+}
+impl EventHandler for RoleEventHandler {
+    fn guild_member_update(&self, _ctx: Context, old: Option<Member>, new: Member) {
+        let old_roles: HashSet<RoleId> =
+            old.map(|m| m.roles.into_iter().collect()).unwrap_or_default();
+        let new_roles: HashSet<RoleId> = new.roles.into_iter().collect();
+        if let Err(err) =
+            self.roles.reconcile_member(new.guild_id, new.user.id, &old_roles, &new_roles)
+        {
+            warn!("Failed to reconcile roles for {} in {}: {}", new.user.id, new.guild_id, err);
+        }
+    }
+}
@@ -0,0 +1,5 @@
+pub mod config;
+pub mod events;
+pub mod reverify_scheduler;
+pub mod roles;
+pub mod verifier;
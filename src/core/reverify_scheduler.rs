@@ -0,0 +1,24 @@
+use core::roles::RoleManager;
+use serenity::model::id::GuildId;
+use std::thread;
+use std::time::Duration;
+
+const SCHEDULER_TICK: Duration = Duration::from_secs(60);
+
+// Periodically sweeps every guild whose reverify interval has elapsed and flushes any deferred
+// role updates whose timeout has lifted. Runs on its own thread for the program's lifetime.
+pub fn spawn_reverify_scheduler(roles: RoleManager, guilds: Vec<GuildId>) {
+    thread::spawn(move || loop {
+        for &guild in &guilds {
+            match roles.should_reverify_all(guild) {
+                Ok(true) => if let Err(err) = roles.reverify_all(guild) {
+                    warn!("Reverify sweep failed for {}: {}", guild, err);
+                },
+                Ok(false) => {}
+                Err(err) => warn!("Failed to check reverify schedule for {}: {}", guild, err),
+            }
+        }
+        roles.flush_expired_deferred_updates();
+        thread::sleep(SCHEDULER_TICK);
+    });
+}
@@ -8,11 +8,43 @@ use serenity::model::prelude::*;
 use std::collections::{HashMap, HashSet};
 use std::mem::drop;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 use std::time::{SystemTime, Duration};
 use roblox::{VerificationSet, VerificationRule, RobloxUserID};
 use util;
 use util::{AtomicSystemTime, ConcurrentCache};
 
+// Avoid reacting to an edit a recent non-manual role update just made to the member itself.
+const RECONCILE_DEBOUNCE: Duration = Duration::from_secs(5);
+
+// Discord's role hierarchy orders by position, breaking ties by role ID.
+fn role_outranked_by(by: (i64, RoleId), role: (i64, RoleId)) -> bool {
+    role <= by
+}
+
+fn is_debounced(last_updated: SystemTime, now: SystemTime) -> bool {
+    now < last_updated + RECONCILE_DEBOUNCE
+}
+
+// `last_swept` must only ever be the timestamp of a *completed* sweep (never an in-progress
+// one), or this would block resuming an interrupted sweep for a full interval after a restart.
+fn is_sweep_due(last_swept: Option<SystemTime>, now: SystemTime, interval: Duration) -> bool {
+    match last_swept {
+        Some(last_swept) => now >= last_swept + interval,
+        None => true,
+    }
+}
+
+fn is_expired(expires_at: SystemTime, now: SystemTime) -> bool {
+    now >= expires_at
+}
+
+// Members fetched per page, and the pause between pages, while `reverify_all` sweeps a
+// guild's membership; keeps it well under Discord's rate limits.
+const REVERIFY_SWEEP_BATCH_SIZE: u64 = 100;
+const REVERIFY_SWEEP_BATCH_PAUSE: Duration = Duration::from_secs(2);
+
 enum VerificationSetStatus {
     NotCompiled,
     Error(String),
@@ -34,13 +66,33 @@ pub struct AssignedRole {
     pub rule: String, pub role_id: RoleId, pub is_assigned: bool,
 }
 pub enum SetRolesStatus {
-    Success, IsAdmin,
+    Success, IsAdmin, Deferred(SystemTime),
 }
 
 struct RoleManagerData {
     config: ConfigManager, database: Database, verifier: Verifier,
     rule_cache: ConcurrentCache<GuildId, RwLock<VerificationSetStatus>>,
     last_update_cache: ConcurrentCache<(GuildId, UserId, bool), AtomicSystemTime>,
+    deferred_updates: RwLock<HashMap<(GuildId, UserId), SystemTime>>,
+    in_flight: ConcurrentCache<(GuildId, UserId), AtomicBool>,
+    sweep_in_flight: ConcurrentCache<GuildId, AtomicBool>,
+}
+
+// Releases a claimed flag when dropped, whether the guarded operation succeeds, errors, or
+// panics.
+struct InFlightGuard(Arc<AtomicBool>);
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+// Claims `flag`, bailing with `busy_message` if it's already held.
+fn claim_flag(flag: Arc<AtomicBool>, busy_message: &str) -> Result<InFlightGuard> {
+    if flag.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        cmd_error!("{}", busy_message);
+    }
+    Ok(InFlightGuard(flag))
 }
 #[derive(Clone)]
 pub struct RoleManager(Arc<RoleManagerData>);
@@ -49,6 +101,8 @@ impl RoleManager {
         RoleManager(Arc::new(RoleManagerData {
             config, database, verifier,
             rule_cache: ConcurrentCache::new(), last_update_cache: ConcurrentCache::new(),
+            deferred_updates: RwLock::new(HashMap::new()), in_flight: ConcurrentCache::new(),
+            sweep_in_flight: ConcurrentCache::new(),
         }))
     }
 
@@ -93,6 +147,37 @@ impl RoleManager {
     pub fn get_configuration(&self, guild: GuildId) -> Result<HashMap<String, ConfiguredRole>> {
         Ok(self.get_configuration_internal(&self.0.database.connect()?, guild)?.0)
     }
+
+    // Checks the bot can actually grant each role, following Discord's position-then-id
+    // role hierarchy ordering. Returns a description of the first unmanageable role, if any.
+    fn check_role_hierarchy(
+        &self, guild: GuildId, role_ids: impl Iterator<Item = RoleId>,
+    ) -> Result<Option<String>> {
+        let my_id = serenity::CACHE.read().user.id;
+        if guild.get()?.owner_id == my_id {
+            return Ok(None) // the owner can assign any role regardless of role positions
+        }
+
+        let roles = guild.roles()?;
+        let me_member = guild.member(my_id)?;
+        let my_position = me_member.roles.iter()
+            .filter_map(|id| roles.get(id).map(|role| (role.position, *id)))
+            .max()
+            .unwrap_or((0, RoleId(guild.0))); // no roles: rank with @everyone, at position 0
+
+        for role_id in role_ids {
+            if let Some(role) = roles.get(&role_id) {
+                if !role_outranked_by(my_position, (role.position, role_id)) {
+                    return Ok(Some(format!(
+                        "Role '{}' cannot be assigned because it is above my highest role.",
+                        role.name,
+                    )))
+                }
+            }
+        }
+        Ok(None)
+    }
+
     fn build_for_guild(
         &self, conn: &DatabaseConnection, guild: GuildId
     ) -> Result<VerificationSetStatus> {
@@ -160,6 +245,10 @@ impl RoleManager {
                     }
                 }
 
+                if let Some(err) = self.check_role_hierarchy(guild, active_rules.values().cloned())? {
+                    return Ok(VerificationSetStatus::Error(err))
+                }
+
                 Ok(VerificationSetStatus::Compiled(set, active_rules))
             }
             Err(Error(box (ErrorKind::CommandError(err), _))) =>
@@ -318,6 +407,16 @@ impl RoleManager {
         &self, guild: GuildId, discord_id: UserId, roblox_id: Option<RobloxUserID>
     ) -> Result<SetRolesStatus> {
         let member = guild.member(discord_id)?;
+
+        // Editing a timed-out member is unreliable and, for nicknames at least, often
+        // rejected outright. Defer the update until the timeout lifts instead of fighting it.
+        if let Some(disabled_until) = member.communication_disabled_until {
+            if disabled_until > SystemTime::now() {
+                self.0.deferred_updates.write().insert((guild, discord_id), disabled_until);
+                return Ok(SetRolesStatus::Deferred(disabled_until))
+            }
+        }
+
         let me_member = guild.member(serenity::CACHE.read().user.id)?;
         let can_access_user = util::can_member_access_member(&me_member, &member)?;
         let do_set_nickname = self.0.config.get(None, ConfigKeys::SetNickname)?;
@@ -383,10 +482,92 @@ impl RoleManager {
         })
     }
 
+    // Claims the `(guild, discord_id)` slot so only one update for this member runs at a
+    // time, bailing with a friendly error if one is already in progress.
+    fn claim_in_flight(&self, guild: GuildId, discord_id: UserId) -> Result<InFlightGuard> {
+        let flag = self.0.in_flight.read(&(guild, discord_id), || Ok(AtomicBool::new(false)))?;
+        claim_flag(flag, "An update is already in progress for this user. Please try again shortly.")
+    }
+
+    // Claims `guild`'s sweep slot so `reverify_all` can't run twice concurrently for the same
+    // guild (e.g. two overlapping scheduler ticks), which would race on the same resume cursor.
+    fn claim_sweep_in_flight(&self, guild: GuildId) -> Result<InFlightGuard> {
+        let flag = self.0.sweep_in_flight.read(&guild, || Ok(AtomicBool::new(false)))?;
+        claim_flag(flag, "A reverification sweep is already running for this server.")
+    }
+
     pub fn update_user(&self, guild: GuildId, discord_id: UserId) -> Result<SetRolesStatus> {
+        let _in_flight = self.claim_in_flight(guild, discord_id)?;
         self.assign_roles(guild, discord_id, self.0.verifier.get_verified_roblox_user(discord_id)?)
     }
 
+    fn managed_roles(&self, guild: GuildId) -> Result<HashSet<RoleId>> {
+        let mut managed = HashSet::new();
+        for (_, config) in self.get_configuration(guild)? {
+            if let Some(role_id) = config.role_id {
+                managed.insert(role_id);
+            }
+        }
+
+        let lock = self.get_rule_cache(guild)?;
+        self.update_cached_verification(&lock, guild, false)?;
+        if let VerificationSetStatus::Compiled(_, ref role_info) = *lock.read() {
+            managed.extend(role_info.values().cloned());
+        }
+        Ok(managed)
+    }
+
+    // Re-syncs a member's roles after they're changed by something other than `assign_roles`
+    // (e.g. a moderator editing roles by hand); see `RoleEventHandler::guild_member_update`.
+    // Expects the member's role sets from before and after the edit.
+    pub fn reconcile_member(
+        &self, guild: GuildId, discord_id: UserId,
+        old_roles: &HashSet<RoleId>, new_roles: &HashSet<RoleId>,
+    ) -> Result<()> {
+        // This update may itself be the member's timeout lifting; flush any assignment we
+        // deferred for them before falling through to the regular reconciliation check. An
+        // in-flight conflict here is benign (something else is already updating this member) and
+        // shouldn't abort reconciling the edit that triggered this call.
+        if let Err(err) = self.flush_deferred(guild, discord_id) {
+            warn!("Failed to flush deferred role update for {} in {}: {}", discord_id, guild, err);
+        }
+
+        let managed = self.managed_roles(guild)?;
+        let changed_managed_role = old_roles.symmetric_difference(new_roles)
+            .any(|role| managed.contains(role));
+        if !changed_managed_role {
+            return Ok(())
+        }
+
+        // Debounce against a recent non-manual update recorded for this member (by
+        // `update_user_with_cooldown` or an earlier reconciliation), so we don't immediately
+        // re-fire on the `guild_member_update` event our own edit caused. Edits made by manual
+        // updates, `reverify_all` or deferred flushes are not recorded here and not debounced.
+        let cache = self.with_cooldown_cache(guild, discord_id, false)?;
+        let now = SystemTime::now();
+        if let Some(last_updated) = cache.load() {
+            if is_debounced(last_updated, now) {
+                return Ok(())
+            }
+        }
+
+        let result = self.update_user(guild, discord_id)?;
+        // Same as `update_user_with_cooldown`: a deferred update made no actual edit, so don't
+        // debounce future reconciliation attempts as if one had happened.
+        match result {
+            SetRolesStatus::Deferred(_) => {}
+            _ => {
+                self.0.database.connect()?.execute_cached(
+                    "INSERT INTO roles_last_updated (\
+                        discord_guild_id, discord_user_id, is_manual, last_updated\
+                    ) VALUES (?1, ?2, ?3, ?4)", (guild, discord_id, false, now),
+                )?;
+                cache.store(Some(now));
+            }
+        }
+        Ok(())
+    }
+
     fn with_cooldown_cache(
         &self, guild_id: GuildId, user_id: UserId, is_manual: bool
     ) -> Result<Arc<AtomicSystemTime>> {
@@ -414,15 +595,122 @@ impl RoleManager {
             }
         }
         let result = self.update_user(guild_id, user_id)?;
-        self.0.database.connect()?.execute_cached(
-            "INSERT INTO roles_last_updated (\
-                discord_guild_id, discord_user_id, is_manual, last_updated\
-            ) VALUES (?1, ?2, ?3, ?4)", (guild_id, user_id, is_manual, now),
-        )?;
-        cache.store(Some(now));
+        // A deferred update didn't actually touch Discord, so don't burn the user's cooldown
+        // on it; the cooldown should only start once the real edit happens.
+        match result {
+            SetRolesStatus::Deferred(_) => {}
+            _ => {
+                self.0.database.connect()?.execute_cached(
+                    "INSERT INTO roles_last_updated (\
+                        discord_guild_id, discord_user_id, is_manual, last_updated\
+                    ) VALUES (?1, ?2, ?3, ?4)", (guild_id, user_id, is_manual, now),
+                )?;
+                cache.store(Some(now));
+            }
+        }
         Ok(result)
     }
 
+    pub fn should_reverify_all(&self, guild: GuildId) -> Result<bool> {
+        let interval = self.0.config.get(None, ConfigKeys::ReverifySweepInterval)?;
+        let last_swept = self.0.database.connect()?.query_cached(
+            "SELECT last_swept FROM guild_reverify_progress WHERE discord_guild_id = ?1", guild,
+        ).get_opt::<SystemTime>()?;
+        Ok(is_sweep_due(last_swept, SystemTime::now(), Duration::from_secs(interval)))
+    }
+
+    // Walks every member of `guild`, re-running `update_user` for anyone currently verified;
+    // members the bot can't access are skipped.
+    // Blocks the calling thread for the sweep's duration (member pages times
+    // `REVERIFY_SWEEP_BATCH_PAUSE`, which can be minutes for a large guild) so call this from a
+    // background task, not the bot's event-processing thread; see `reverify_scheduler`.
+    pub fn reverify_all(&self, guild: GuildId) -> Result<()> {
+        let _sweep_guard = self.claim_sweep_in_flight(guild)?;
+        let conn = self.0.database.connect()?;
+        let me_member = guild.member(serenity::CACHE.read().user.id)?;
+
+        conn.execute_cached(
+            "INSERT OR IGNORE INTO guild_reverify_progress (\
+                discord_guild_id, cursor_user_id, last_swept\
+            ) VALUES (?1, NULL, NULL)", guild,
+        )?;
+        let mut cursor = conn.query_cached(
+            "SELECT cursor_user_id FROM guild_reverify_progress WHERE discord_guild_id = ?1",
+            guild,
+        ).get_opt::<UserId>()?;
+
+        loop {
+            let members = guild.members(REVERIFY_SWEEP_BATCH_SIZE, cursor)?;
+            if members.is_empty() {
+                break
+            }
+
+            for member in &members {
+                cursor = Some(member.user.id);
+                if !util::can_member_access_member(&me_member, member)? {
+                    continue
+                }
+                if self.0.verifier.get_verified_roblox_user(member.user.id)?.is_some() {
+                    if let Err(err) = self.update_user(guild, member.user.id) {
+                        warn!("Failed to reverify {} in {}: {}", member.user.id, guild, err);
+                    }
+                }
+            }
+
+            // Only the resume cursor is persisted here. `last_swept` gates when the next
+            // sweep is allowed to start (see `should_reverify_all`), so it must only move
+            // once the full pass over the membership below actually completes — otherwise a
+            // restart mid-sweep looks like a recent completed sweep and blocks resuming.
+            conn.execute_cached(
+                "UPDATE guild_reverify_progress SET cursor_user_id = ?2 \
+                 WHERE discord_guild_id = ?1", (guild, cursor),
+            )?;
+            thread::sleep(REVERIFY_SWEEP_BATCH_PAUSE);
+        }
+
+        conn.execute_cached(
+            "UPDATE guild_reverify_progress SET cursor_user_id = NULL, last_swept = ?2 \
+             WHERE discord_guild_id = ?1", (guild, SystemTime::now()),
+        )?;
+        Ok(())
+    }
+
+    // Applies a role assignment deferred by `assign_roles` for `(guild, discord_id)`, if one
+    // is pending and the member is no longer timed out. `Ok(None)` if nothing was deferred or
+    // the member is still timed out. Called from `reconcile_member` so it fires as soon as a
+    // member's timeout is lifted.
+    pub fn flush_deferred(&self, guild: GuildId, discord_id: UserId) -> Result<Option<SetRolesStatus>> {
+        if !self.0.deferred_updates.read().contains_key(&(guild, discord_id)) {
+            return Ok(None)
+        }
+        // Check the member's current timeout rather than the recorded expiry, so a timeout
+        // lifted early is picked up too.
+        let member = guild.member(discord_id)?;
+        let still_timed_out = member.communication_disabled_until
+            .map_or(false, |until| !is_expired(until, SystemTime::now()));
+        if still_timed_out {
+            return Ok(None)
+        }
+        self.0.deferred_updates.write().remove(&(guild, discord_id));
+        Ok(Some(self.update_user(guild, discord_id)?))
+    }
+
+    // Flushes every deferred update whose timeout has elapsed. Polled by `reverify_scheduler` so
+    // roles catch up even without a `guild_member_update` event.
+    pub fn flush_expired_deferred_updates(&self) {
+        let now = SystemTime::now();
+        let expired: Vec<(GuildId, UserId)> = self.0.deferred_updates.read().iter()
+            .filter(|&(_, &expires_at)| is_expired(expires_at, now))
+            .map(|(&key, _)| key)
+            .collect();
+        for (guild, discord_id) in expired {
+            self.0.deferred_updates.write().remove(&(guild, discord_id));
+            if let Err(err) = self.update_user(guild, discord_id) {
+                warn!("Failed to apply deferred role update for {} in {}: {}", discord_id, guild, err);
+            }
+        }
+    }
+
     pub fn explain_rule_set(&self, guild: GuildId) -> Result<String> {
         let lock = self.get_rule_cache(guild)?;
         self.update_cached_verification(&lock, guild, false)?;
@@ -437,4 +725,58 @@ impl RoleManager {
     pub fn clear_rule_cache(&self) {
         self.0.rule_cache.clear_cache()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn role_hierarchy_orders_by_position_then_id() {
+        assert!(role_outranked_by((5, RoleId(10)), (5, RoleId(9))));
+        assert!(role_outranked_by((5, RoleId(10)), (5, RoleId(10))));
+        assert!(!role_outranked_by((5, RoleId(10)), (5, RoleId(11))));
+        assert!(role_outranked_by((5, RoleId(10)), (4, RoleId(999))));
+        assert!(!role_outranked_by((5, RoleId(10)), (6, RoleId(1))));
+    }
+
+    #[test]
+    fn debounce_window() {
+        let last_updated = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        assert!(is_debounced(last_updated, last_updated));
+        assert!(is_debounced(last_updated, last_updated + RECONCILE_DEBOUNCE - Duration::from_secs(1)));
+        assert!(!is_debounced(last_updated, last_updated + RECONCILE_DEBOUNCE));
+        assert!(!is_debounced(last_updated, last_updated + RECONCILE_DEBOUNCE + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn sweep_due_when_never_swept() {
+        assert!(is_sweep_due(None, SystemTime::UNIX_EPOCH, Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn sweep_due_respects_interval() {
+        let last_swept = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let interval = Duration::from_secs(60);
+        assert!(!is_sweep_due(Some(last_swept), last_swept + interval - Duration::from_secs(1), interval));
+        assert!(is_sweep_due(Some(last_swept), last_swept + interval, interval));
+    }
+
+    #[test]
+    fn deferred_update_expiry() {
+        let expires_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        assert!(!is_expired(expires_at, expires_at - Duration::from_secs(1)));
+        assert!(is_expired(expires_at, expires_at));
+        assert!(is_expired(expires_at, expires_at + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn claim_flag_conflicts_then_releases_on_drop() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let guard = claim_flag(flag.clone(), "busy").expect("first claim should succeed");
+        // A second claim attempt while the first is held must fail.
+        assert!(claim_flag(flag.clone(), "busy").is_err());
+        drop(guard);
+        assert!(claim_flag(flag.clone(), "busy").is_ok());
+    }
 }
\ No newline at end of file